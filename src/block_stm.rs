@@ -0,0 +1,655 @@
+//! Block-STM style optimistic parallel executor.
+//!
+//! This is an alternative to `serial_execute` in main.rs: transactions are
+//! speculatively executed against a multi-version store and validated
+//! against each other, instead of being replayed one at a time against a
+//! single `StateDB`. A correct run produces the same final `MapState` and
+//! per-tx `TxRWSet`s as `serial_execute` over the same block.
+
+use crate::db::{MapState, StateDB};
+use crate::types::{FlatKey, FlatValue, Tx, TxRWSet};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Mutex, RwLock};
+
+/// A writer's `(tx index, incarnation)`, used to tag the value a read
+/// resolved to so a later validation pass can tell whether it is stale.
+type Version = (usize, usize);
+
+struct VersionedValue {
+    value: FlatValue,
+    incarnation: usize,
+    /// Set while the writer's current incarnation hasn't finished executing
+    /// yet; readers that land on an estimate must abort and retry later.
+    estimate: bool,
+}
+
+enum ReadOutcome {
+    Value(FlatValue, Version),
+    NotFound,
+    Dependency(usize),
+}
+
+/// Multi-version memory: for each key, every writer's value is kept
+/// separately instead of being overwritten in place.
+struct MVMemory {
+    data: RwLock<HashMap<FlatKey, std::collections::BTreeMap<usize, VersionedValue>>>,
+    /// Every tx index (across the whole block) that writes each key,
+    /// computed once up front from the static program text. Lets `read`
+    /// tell "no writer has committed *yet*" (a lower tx just hasn't run)
+    /// apart from "no writer *ever* touches this key below `tx_idx`" (the
+    /// key's value is genuinely its untouched EVM default of zero).
+    write_index: HashMap<FlatKey, BTreeSet<usize>>,
+}
+
+impl MVMemory {
+    fn new(txs: &[Tx]) -> Self {
+        let mut write_index: HashMap<FlatKey, BTreeSet<usize>> = HashMap::new();
+        for (idx, tx) in txs.iter().enumerate() {
+            for key in tx.flat_rw_set().writes {
+                write_index.entry(key).or_default().insert(idx);
+            }
+        }
+        MVMemory {
+            data: RwLock::new(HashMap::new()),
+            write_index,
+        }
+    }
+
+    /// Returns the value written by the highest tx index below `tx_idx`.
+    fn read(&self, key: FlatKey, tx_idx: usize) -> ReadOutcome {
+        let data = self.data.read().unwrap();
+        if let Some(versions) = data.get(&key) {
+            match versions.range(..tx_idx).next_back() {
+                Some((&idx, v)) if v.estimate => return ReadOutcome::Dependency(idx),
+                Some((&idx, v)) => return ReadOutcome::Value(v.value, (idx, v.incarnation)),
+                None => {}
+            }
+        }
+        drop(data);
+
+        // No version has been written yet, but a lower-indexed tx is still
+        // going to write one -- abort and retry once it has, same as
+        // landing on an ESTIMATE.
+        match self.write_index.get(&key).and_then(|ws| ws.range(..tx_idx).next_back()) {
+            Some(&idx) => ReadOutcome::Dependency(idx),
+            None => ReadOutcome::NotFound,
+        }
+    }
+
+    fn write(&self, key: FlatKey, tx_idx: usize, incarnation: usize, value: FlatValue) {
+        let mut data = self.data.write().unwrap();
+        data.entry(key).or_default().insert(
+            tx_idx,
+            VersionedValue {
+                value,
+                incarnation,
+                estimate: false,
+            },
+        );
+    }
+
+    fn mark_estimate(&self, key: FlatKey, tx_idx: usize) {
+        let mut data = self.data.write().unwrap();
+        if let Some(v) = data.get_mut(&key).and_then(|versions| versions.get_mut(&tx_idx)) {
+            v.estimate = true;
+        }
+    }
+
+    /// Folds every key down to the value visible after all `n` txs, in the
+    /// same shape `serial_execute` would have left in a `MapState`.
+    fn into_map_state(self, n: usize) -> MapState {
+        let mut state = MapState::new();
+        for (key, versions) in self.data.into_inner().unwrap() {
+            if let Some((_, v)) = versions.range(..n).next_back() {
+                state.set_state(key, v.value);
+            }
+        }
+        state
+    }
+}
+
+struct ExecutionOutcome {
+    read_set: Vec<(FlatKey, Version)>,
+    write_set: Vec<(FlatKey, FlatValue)>,
+    rw: TxRWSet,
+}
+
+enum ExecResult {
+    Done(ExecutionOutcome),
+    /// Aborted because a read landed on an in-flight writer (marked
+    /// ESTIMATE) or on a key a lower-indexed tx is statically known to
+    /// write but hasn't yet. Carries that blocking tx index so the
+    /// scheduler can avoid re-picking this tx before it makes progress.
+    Blocked(usize),
+}
+
+/// Sentinel read-set version for a `SLOAD` confirmed, from the block's
+/// static write set, to have no writer at all below `tx_idx`. Such a read
+/// always resolves to this same default, so validation just checks it's
+/// still the case rather than comparing against a real writer version.
+const NO_WRITER: Version = (usize::MAX, 0);
+
+/// Speculatively runs `tx`'s program against the multi-version store.
+/// Mirrors `exec_tx`'s stack semantics exactly, except reads that land on
+/// an in-flight (ESTIMATE) writer, or on a key a lower-indexed tx writes
+/// but hasn't yet, abort the attempt instead of reading garbage.
+fn try_execute(tx: &Tx, tx_idx: usize, mv: &MVMemory) -> ExecResult {
+    let mut stack: Vec<FlatValue> = Vec::new();
+    let mut read_set: Vec<(FlatKey, Version)> = Vec::new();
+    let mut local_writes: HashMap<FlatKey, FlatValue> = HashMap::new();
+    let mut reads = BTreeSet::new();
+    let mut writes = BTreeSet::new();
+
+    for op in tx.program.iter() {
+        match op {
+            crate::types::MicroOp::SLOAD { key } => {
+                let h = key.flat();
+                reads.insert(h);
+
+                if let Some(&v) = local_writes.get(&h) {
+                    stack.push(v);
+                } else {
+                    match mv.read(h, tx_idx) {
+                        ReadOutcome::Value(v, version) => {
+                            stack.push(v);
+                            read_set.push((h, version));
+                        }
+                        // No writer touches this key anywhere below
+                        // `tx_idx` in the block, so it's untouched and
+                        // reads as its EVM default of zero.
+                        ReadOutcome::NotFound => {
+                            stack.push(FlatValue::ZERO);
+                            read_set.push((h, NO_WRITER));
+                        }
+                        // A lower-indexed tx is going to write this key but
+                        // hasn't yet -- with the scheduler free to run tx
+                        // indices out of order this is the expected case
+                        // for any tx with a real dependency, not a missing-
+                        // state error, so abort and retry once it has.
+                        ReadOutcome::Dependency(idx) => return ExecResult::Blocked(idx),
+                    }
+                }
+            }
+            crate::types::MicroOp::SSTORE { key } => {
+                let h = key.flat();
+                let v = stack.pop().expect("stack underflow on SSTORE");
+                local_writes.insert(h, v);
+                writes.insert(h);
+            }
+            crate::types::MicroOp::ADD { imm } => {
+                let a = stack.pop().unwrap_or(FlatValue::ZERO);
+                stack.push(a + *imm);
+            }
+            crate::types::MicroOp::MUL => {
+                let b = stack.pop().expect("stack underflow on MUL");
+                let a = stack.pop().expect("stack underflow on MUL");
+                stack.push(a.wrapping_mul(b));
+            }
+            crate::types::MicroOp::SUB => {
+                let b = stack.pop().expect("stack underflow on SUB");
+                let a = stack.pop().expect("stack underflow on SUB");
+                stack.push(a.wrapping_sub(b));
+            }
+            crate::types::MicroOp::DUP => {
+                let top = *stack.last().expect("stack underflow on DUP");
+                stack.push(top);
+            }
+            crate::types::MicroOp::POP => {
+                stack.pop().expect("stack underflow on POP");
+            }
+            crate::types::MicroOp::KECCAK { n } => {
+                let len = stack.len();
+                assert!(len >= *n, "stack underflow on KECCAK");
+                let words = stack.split_off(len - n);
+                stack.push(FlatValue::keccak(&words));
+            }
+            crate::types::MicroOp::NOOP => {}
+        }
+    }
+
+    ExecResult::Done(ExecutionOutcome {
+        read_set,
+        write_set: local_writes.into_iter().collect(),
+        rw: TxRWSet {
+            id: tx.id,
+            reads,
+            writes,
+        },
+    })
+}
+
+/// Re-resolves every read recorded during the last execution and checks it
+/// still lands on the same writer version.
+fn validate(read_set: &[(FlatKey, Version)], tx_idx: usize, mv: &MVMemory) -> bool {
+    read_set.iter().all(|&(key, expected)| match mv.read(key, tx_idx) {
+        ReadOutcome::Value(_, version) => version == expected,
+        ReadOutcome::NotFound => expected == NO_WRITER,
+        ReadOutcome::Dependency(_) => false,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TxState {
+    ReadyToExecute,
+    Executing,
+    Executed,
+    Validated,
+}
+
+struct SchedulerState {
+    status: Vec<TxState>,
+    incarnation: Vec<usize>,
+    /// Bumped every time a tx is (re)queued for validation, so a validation
+    /// result that lost a race against a newer abort can be told apart from
+    /// a current one and dropped.
+    validation_gen: Vec<usize>,
+    /// Set by `requeue_blocked` to the tx index a blocked attempt stalled
+    /// on, so `next_task` can skip re-picking it until that index has made
+    /// progress instead of busy-spinning on the same doomed attempt.
+    waiting_on: Vec<Option<usize>>,
+    pending_execution: BTreeSet<usize>,
+    pending_validation: BTreeSet<usize>,
+    in_flight: usize,
+}
+
+enum Task {
+    Execute(usize, usize),
+    Validate(usize, usize),
+}
+
+/// Collaborative scheduler: `n_workers` threads each loop claiming whatever
+/// execute/validate task is available until every tx has executed and
+/// validated cleanly.
+struct Scheduler {
+    n: usize,
+    state: Mutex<SchedulerState>,
+}
+
+impl Scheduler {
+    fn new(n: usize) -> Self {
+        Scheduler {
+            n,
+            state: Mutex::new(SchedulerState {
+                status: vec![TxState::ReadyToExecute; n],
+                incarnation: vec![0; n],
+                validation_gen: vec![0; n],
+                waiting_on: vec![None; n],
+                pending_execution: (0..n).collect(),
+                pending_validation: BTreeSet::new(),
+                in_flight: 0,
+            }),
+        }
+    }
+
+    /// Validation tasks take priority over execution tasks, matching the
+    /// paper's preference for draining re-validations before starting new
+    /// speculative work.
+    fn next_task(&self) -> Option<Task> {
+        let mut s = self.state.lock().unwrap();
+        if let Some(&idx) = s.pending_validation.iter().next() {
+            s.pending_validation.remove(&idx);
+            s.in_flight += 1;
+            return Some(Task::Validate(idx, s.validation_gen[idx]));
+        }
+
+        if !s.pending_execution.is_empty() {
+            // Prefer a tx that isn't known to be stalled on another one
+            // that hasn't made progress yet, so workers don't keep
+            // re-picking the same blocked attempt while its dependency is
+            // still mid-flight; fall back to the lowest pending index if
+            // every candidate is still waiting.
+            let pick = s
+                .pending_execution
+                .iter()
+                .copied()
+                .find(|&idx| match s.waiting_on[idx] {
+                    Some(dep) => matches!(s.status[dep], TxState::Executed | TxState::Validated),
+                    None => true,
+                })
+                .unwrap_or(*s.pending_execution.iter().next().unwrap());
+
+            s.pending_execution.remove(&pick);
+            s.status[pick] = TxState::Executing;
+            s.waiting_on[pick] = None;
+            s.in_flight += 1;
+            return Some(Task::Execute(pick, s.incarnation[pick]));
+        }
+
+        None
+    }
+
+    fn all_done(&self) -> bool {
+        let s = self.state.lock().unwrap();
+        s.in_flight == 0
+            && s.pending_execution.is_empty()
+            && s.pending_validation.is_empty()
+            && s.status.iter().all(|st| *st == TxState::Validated)
+    }
+
+    fn complete_execution(&self, idx: usize) {
+        let mut s = self.state.lock().unwrap();
+        s.in_flight -= 1;
+        s.status[idx] = TxState::Executed;
+        s.validation_gen[idx] += 1;
+        s.pending_validation.insert(idx);
+    }
+
+    fn requeue_blocked(&self, idx: usize, waiting_on: usize) {
+        let mut s = self.state.lock().unwrap();
+        s.in_flight -= 1;
+        s.status[idx] = TxState::ReadyToExecute;
+        s.waiting_on[idx] = Some(waiting_on);
+        s.pending_execution.insert(idx);
+    }
+
+    /// `mark_estimates` runs only for a current, failed validation, under the
+    /// scheduler lock, so a superseded result leaves no ESTIMATE marks behind.
+    fn complete_validation(&self, idx: usize, gen: usize, ok: bool, mark_estimates: impl FnOnce()) {
+        let mut s = self.state.lock().unwrap();
+        s.in_flight -= 1;
+        if gen != s.validation_gen[idx] {
+            // A concurrent abort already superseded this result.
+            return;
+        }
+        if ok {
+            if s.status[idx] == TxState::Executed {
+                s.status[idx] = TxState::Validated;
+            }
+            return;
+        }
+
+        mark_estimates();
+        s.incarnation[idx] += 1;
+        s.status[idx] = TxState::ReadyToExecute;
+        s.pending_execution.insert(idx);
+
+        let n = self.n;
+        for j in (idx + 1)..n {
+            if matches!(s.status[j], TxState::Executed | TxState::Validated) {
+                s.status[j] = TxState::Executed;
+                s.validation_gen[j] += 1;
+                s.pending_validation.insert(j);
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn worker_loop(
+    txs: &[Tx],
+    mv: &MVMemory,
+    scheduler: &Scheduler,
+    last_read_set: &[Mutex<Vec<(FlatKey, Version)>>],
+    last_write_set: &[Mutex<Vec<FlatKey>>],
+    rw_sets: &[Mutex<Option<TxRWSet>>],
+) {
+    loop {
+        match scheduler.next_task() {
+            Some(Task::Execute(idx, incarnation)) => match try_execute(&txs[idx], idx, mv) {
+                ExecResult::Blocked(waiting_on) => scheduler.requeue_blocked(idx, waiting_on),
+                ExecResult::Done(outcome) => {
+                    for &(key, val) in &outcome.write_set {
+                        mv.write(key, idx, incarnation, val);
+                    }
+                    *last_read_set[idx].lock().unwrap() = outcome.read_set;
+                    *last_write_set[idx].lock().unwrap() =
+                        outcome.write_set.iter().map(|&(k, _)| k).collect();
+                    *rw_sets[idx].lock().unwrap() = Some(outcome.rw);
+                    scheduler.complete_execution(idx);
+                }
+            },
+            Some(Task::Validate(idx, gen)) => {
+                let read_set = last_read_set[idx].lock().unwrap().clone();
+                let ok = validate(&read_set, idx, mv);
+                scheduler.complete_validation(idx, gen, ok, || {
+                    for key in last_write_set[idx].lock().unwrap().iter() {
+                        mv.mark_estimate(*key, idx);
+                    }
+                });
+            }
+            None => {
+                if scheduler.all_done() {
+                    return;
+                }
+                std::thread::yield_now();
+            }
+        }
+    }
+}
+
+/// Runs `txs` with `n_workers` threads doing Block-STM style optimistic
+/// concurrency control, returning the same `(MapState, Vec<TxRWSet>)` shape
+/// `serial_execute` would have produced for the same block.
+pub fn parallel_execute(txs: &[Tx], n_workers: usize) -> (MapState, Vec<TxRWSet>) {
+    let n = txs.len();
+    if n == 0 {
+        return (MapState::new(), Vec::new());
+    }
+    let n_workers = n_workers.max(1);
+
+    let mv = MVMemory::new(txs);
+    let scheduler = Scheduler::new(n);
+    let last_read_set: Vec<Mutex<Vec<(FlatKey, Version)>>> =
+        (0..n).map(|_| Mutex::new(Vec::new())).collect();
+    let last_write_set: Vec<Mutex<Vec<FlatKey>>> = (0..n).map(|_| Mutex::new(Vec::new())).collect();
+    let rw_sets: Vec<Mutex<Option<TxRWSet>>> = (0..n).map(|_| Mutex::new(None)).collect();
+
+    std::thread::scope(|scope| {
+        for _ in 0..n_workers {
+            scope.spawn(|| {
+                worker_loop(txs, &mv, &scheduler, &last_read_set, &last_write_set, &rw_sets)
+            });
+        }
+    });
+
+    let state = mv.into_map_state(n);
+    let results = rw_sets
+        .into_iter()
+        .map(|m| m.into_inner().unwrap().expect("every tx must finish"))
+        .collect();
+    (state, results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Key, MicroOp};
+
+    fn key(tag: u8) -> Key {
+        Key {
+            address: [tag; 20],
+            slot: [tag; 32],
+        }
+    }
+
+    /// Three txs chained through shared keys (tx1 reads what tx0 wrote, tx2
+    /// reads what tx1 wrote), so a tx picked up before its dependency has
+    /// written is blocked and re-executed later.
+    fn chained_block() -> Vec<Tx> {
+        let a = key(1);
+        let b = key(2);
+        let c = key(3);
+
+        vec![
+            Tx {
+                id: 0,
+                reads: vec![],
+                writes: vec![a.clone()],
+                gas_hint: 10,
+                metadata: None,
+                program: vec![
+                    MicroOp::ADD {
+                        imm: FlatValue::from_u64(7),
+                    },
+                    MicroOp::SSTORE { key: a.clone() },
+                ],
+            },
+            Tx {
+                id: 1,
+                reads: vec![a.clone()],
+                writes: vec![b.clone()],
+                gas_hint: 10,
+                metadata: None,
+                program: vec![
+                    MicroOp::SLOAD { key: a.clone() },
+                    MicroOp::ADD {
+                        imm: FlatValue::from_u64(1),
+                    },
+                    MicroOp::SSTORE { key: b.clone() },
+                ],
+            },
+            Tx {
+                id: 2,
+                reads: vec![b.clone()],
+                writes: vec![c.clone()],
+                gas_hint: 10,
+                metadata: None,
+                program: vec![
+                    MicroOp::SLOAD { key: b.clone() },
+                    MicroOp::ADD {
+                        imm: FlatValue::from_u64(1),
+                    },
+                    MicroOp::SSTORE { key: c.clone() },
+                ],
+            },
+        ]
+    }
+
+    /// `n` txs chained end to end: tx `i` (for `i > 0`) reads tx `i - 1`'s
+    /// write. With the scheduler free to grab execution tasks out of order,
+    /// any tx above index 0 routinely starts before its dependency has
+    /// written anything, which regression-tests the `ReadOutcome::Dependency`
+    /// handling in `try_execute`.
+    fn linear_chain(n: usize) -> Vec<Tx> {
+        (0..n)
+            .map(|i| {
+                let w = key((i + 1) as u8);
+                let mut program = Vec::new();
+                let reads = if i == 0 {
+                    Vec::new()
+                } else {
+                    let r = key(i as u8);
+                    program.push(MicroOp::SLOAD { key: r.clone() });
+                    vec![r]
+                };
+                program.push(MicroOp::ADD {
+                    imm: FlatValue::from_u64(1),
+                });
+                program.push(MicroOp::SSTORE { key: w.clone() });
+
+                Tx {
+                    id: i as u64,
+                    reads,
+                    writes: vec![w],
+                    gas_hint: 10,
+                    metadata: None,
+                    program,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn long_dependency_chain_does_not_panic() {
+        let txs = linear_chain(40);
+
+        let mut serial_state = MapState::new();
+        let serial_results: Vec<TxRWSet> = txs
+            .iter()
+            .map(|tx| crate::exec_tx(tx, &mut serial_state))
+            .collect();
+
+        // Several runs to give the scheduler plenty of chances to start a
+        // higher-indexed tx before its dependency has written anything.
+        for _ in 0..20 {
+            let (parallel_state, parallel_results) = parallel_execute(&txs, 8);
+
+            for tx in &txs {
+                for op in tx.program.iter() {
+                    if let MicroOp::SSTORE { key } = op {
+                        let h = key.flat();
+                        assert_eq!(
+                            serial_state.get_state(&h),
+                            parallel_state.get_state(&h),
+                            "state for key {:?} diverged",
+                            h
+                        );
+                    }
+                }
+            }
+
+            assert_eq!(parallel_results.len(), serial_results.len());
+            for (p, s) in parallel_results.iter().zip(serial_results.iter()) {
+                assert_eq!(p.id, s.id);
+                assert_eq!(p.reads, s.reads);
+                assert_eq!(p.writes, s.writes);
+            }
+        }
+    }
+
+    /// A read of a key nobody in the block ever writes -- the normal case
+    /// for `--cold-ratio`/neutral-index reads out of `generate_block` --
+    /// must resolve to the EVM default of zero instead of blocking
+    /// forever, since no writer is ever coming.
+    #[test]
+    fn read_of_never_written_key_defaults_to_zero_instead_of_hanging() {
+        let untouched = key(0xaa);
+        let w = key(1);
+        let untouched_flat = untouched.flat();
+
+        let txs = vec![Tx {
+            id: 0,
+            reads: vec![untouched.clone()],
+            writes: vec![w.clone()],
+            gas_hint: 10,
+            metadata: None,
+            program: vec![
+                MicroOp::SLOAD { key: untouched },
+                MicroOp::ADD {
+                    imm: FlatValue::from_u64(1),
+                },
+                MicroOp::SSTORE { key: w.clone() },
+            ],
+        }];
+
+        let (state, results) = parallel_execute(&txs, 4);
+
+        assert_eq!(state.get_state(&w.flat()), Some(FlatValue::from_u64(1)));
+        assert_eq!(results.len(), 1);
+        assert!(results[0].reads.contains(&untouched_flat));
+    }
+
+    #[test]
+    fn matches_serial_execution() {
+        let txs = chained_block();
+
+        let mut serial_state = MapState::new();
+        let serial_results: Vec<TxRWSet> = txs
+            .iter()
+            .map(|tx| crate::exec_tx(tx, &mut serial_state))
+            .collect();
+
+        let (parallel_state, parallel_results) = parallel_execute(&txs, 4);
+
+        for tx in &txs {
+            for op in tx.program.iter() {
+                if let MicroOp::SSTORE { key } = op {
+                    let h = key.flat();
+                    assert_eq!(
+                        serial_state.get_state(&h),
+                        parallel_state.get_state(&h),
+                        "state for key {:?} diverged",
+                        h
+                    );
+                }
+            }
+        }
+
+        assert_eq!(parallel_results.len(), serial_results.len());
+        for (p, s) in parallel_results.iter().zip(serial_results.iter()) {
+            assert_eq!(p.id, s.id);
+            assert_eq!(p.reads, s.reads);
+            assert_eq!(p.writes, s.writes);
+        }
+    }
+}
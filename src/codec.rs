@@ -0,0 +1,304 @@
+//! Compact fixed-layout binary encoding for `Vec<Tx>`, used as a faster
+//! alternative to the verbose hex-JSON format for large generated blocks.
+//!
+//! Layout (all integers little-endian):
+//!   u64 tx_count
+//!   per tx:
+//!     u64 id
+//!     u64 gas_hint
+//!     u8 has_metadata, then (u32 len + utf8 bytes) if set
+//!     u32 reads_len, then reads_len * (20-byte address + 32-byte slot)
+//!     u32 writes_len, then writes_len * (20-byte address + 32-byte slot)
+//!     u32 program_len, then per op: u8 tag + operands
+//!       0 = SLOAD  { key } -> 52-byte key
+//!       1 = SSTORE { key } -> 52-byte key
+//!       2 = ADD    { imm } -> 32-byte FlatValue
+//!       3 = NOOP           -> (nothing)
+//!       4 = MUL            -> (nothing)
+//!       5 = SUB            -> (nothing)
+//!       6 = DUP            -> (nothing)
+//!       7 = POP            -> (nothing)
+//!       8 = KECCAK { n }   -> 4-byte u32 n
+
+use crate::types::{Address, FlatValue, Key, MicroOp, Slot, Tx};
+
+fn push_key(buf: &mut Vec<u8>, key: &Key) {
+    buf.extend_from_slice(&key.address);
+    buf.extend_from_slice(&key.slot);
+}
+
+fn read_key(bytes: &[u8], pos: &mut usize) -> Key {
+    let mut address: Address = [0u8; 20];
+    address.copy_from_slice(&bytes[*pos..*pos + 20]);
+    *pos += 20;
+    let mut slot: Slot = [0u8; 32];
+    slot.copy_from_slice(&bytes[*pos..*pos + 32]);
+    *pos += 32;
+    Key { address, slot }
+}
+
+pub fn encode_block(txs: &[Tx]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(txs.len() as u64).to_le_bytes());
+
+    for tx in txs {
+        buf.extend_from_slice(&tx.id.to_le_bytes());
+        buf.extend_from_slice(&tx.gas_hint.to_le_bytes());
+
+        match &tx.metadata {
+            Some(m) => {
+                buf.push(1);
+                buf.extend_from_slice(&(m.len() as u32).to_le_bytes());
+                buf.extend_from_slice(m.as_bytes());
+            }
+            None => buf.push(0),
+        }
+
+        buf.extend_from_slice(&(tx.reads.len() as u32).to_le_bytes());
+        for k in &tx.reads {
+            push_key(&mut buf, k);
+        }
+        buf.extend_from_slice(&(tx.writes.len() as u32).to_le_bytes());
+        for k in &tx.writes {
+            push_key(&mut buf, k);
+        }
+
+        buf.extend_from_slice(&(tx.program.len() as u32).to_le_bytes());
+        for op in &tx.program {
+            match op {
+                MicroOp::SLOAD { key } => {
+                    buf.push(0);
+                    push_key(&mut buf, key);
+                }
+                MicroOp::SSTORE { key } => {
+                    buf.push(1);
+                    push_key(&mut buf, key);
+                }
+                MicroOp::ADD { imm } => {
+                    buf.push(2);
+                    buf.extend_from_slice(&imm.to_le_bytes());
+                }
+                MicroOp::NOOP => buf.push(3),
+                MicroOp::MUL => buf.push(4),
+                MicroOp::SUB => buf.push(5),
+                MicroOp::DUP => buf.push(6),
+                MicroOp::POP => buf.push(7),
+                MicroOp::KECCAK { n } => {
+                    buf.push(8);
+                    buf.extend_from_slice(&(*n as u32).to_le_bytes());
+                }
+            }
+        }
+    }
+
+    buf
+}
+
+pub fn decode_block(bytes: &[u8]) -> Vec<Tx> {
+    let mut pos = 0usize;
+    let tx_count = u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap()) as usize;
+    pos += 8;
+
+    let mut txs = Vec::with_capacity(tx_count);
+    for _ in 0..tx_count {
+        let id = u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        pos += 8;
+        let gas_hint = u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        pos += 8;
+
+        let has_metadata = bytes[pos];
+        pos += 1;
+        let metadata = if has_metadata == 1 {
+            let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            pos += 4;
+            let s = String::from_utf8(bytes[pos..pos + len].to_vec())
+                .expect("corrupt metadata utf8");
+            pos += len;
+            Some(s)
+        } else {
+            None
+        };
+
+        let reads_len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4;
+        let mut reads = Vec::with_capacity(reads_len);
+        for _ in 0..reads_len {
+            reads.push(read_key(bytes, &mut pos));
+        }
+
+        let writes_len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4;
+        let mut writes = Vec::with_capacity(writes_len);
+        for _ in 0..writes_len {
+            writes.push(read_key(bytes, &mut pos));
+        }
+
+        let program_len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4;
+        let mut program = Vec::with_capacity(program_len);
+        for _ in 0..program_len {
+            let tag = bytes[pos];
+            pos += 1;
+            let op = match tag {
+                0 => MicroOp::SLOAD {
+                    key: read_key(bytes, &mut pos),
+                },
+                1 => MicroOp::SSTORE {
+                    key: read_key(bytes, &mut pos),
+                },
+                2 => {
+                    let imm = FlatValue::from_le_bytes(bytes[pos..pos + 32].try_into().unwrap());
+                    pos += 32;
+                    MicroOp::ADD { imm }
+                }
+                3 => MicroOp::NOOP,
+                4 => MicroOp::MUL,
+                5 => MicroOp::SUB,
+                6 => MicroOp::DUP,
+                7 => MicroOp::POP,
+                8 => {
+                    let n = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+                    pos += 4;
+                    MicroOp::KECCAK { n }
+                }
+                _ => panic!("unknown opcode tag {}", tag),
+            };
+            program.push(op);
+        }
+
+        txs.push(Tx {
+            id,
+            reads,
+            writes,
+            gas_hint,
+            metadata,
+            program,
+        });
+    }
+
+    txs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(tag: u8) -> Key {
+        Key {
+            address: [tag; 20],
+            slot: [tag; 32],
+        }
+    }
+
+    struct TestCase {
+        name: &'static str,
+        txs: Vec<Tx>,
+    }
+
+    #[test]
+    fn test_round_trip_multiple_cases() {
+        let test_cases = vec![
+            TestCase {
+                name: "empty_block",
+                txs: vec![],
+            },
+            TestCase {
+                name: "single_tx_no_ops",
+                txs: vec![Tx {
+                    id: 0,
+                    reads: vec![],
+                    writes: vec![],
+                    gas_hint: 0,
+                    metadata: None,
+                    program: vec![MicroOp::NOOP],
+                }],
+            },
+            TestCase {
+                name: "tx_with_metadata_and_all_op_kinds",
+                txs: vec![Tx {
+                    id: 7,
+                    reads: vec![key(1), key(2)],
+                    writes: vec![key(3)],
+                    gas_hint: 42,
+                    metadata: Some("hello".to_string()),
+                    program: vec![
+                        MicroOp::SLOAD { key: key(1) },
+                        MicroOp::SLOAD { key: key(2) },
+                        MicroOp::KECCAK { n: 2 },
+                        MicroOp::ADD {
+                            imm: FlatValue::from_u64(9),
+                        },
+                        MicroOp::DUP,
+                        MicroOp::MUL,
+                        MicroOp::SUB,
+                        MicroOp::POP,
+                        MicroOp::SSTORE { key: key(3) },
+                        MicroOp::NOOP,
+                    ],
+                }],
+            },
+            TestCase {
+                name: "multiple_txs",
+                txs: vec![
+                    Tx {
+                        id: 0,
+                        reads: vec![key(1)],
+                        writes: vec![key(2)],
+                        gas_hint: 10,
+                        metadata: None,
+                        program: vec![MicroOp::SLOAD { key: key(1) }],
+                    },
+                    Tx {
+                        id: 1,
+                        reads: vec![],
+                        writes: vec![key(4)],
+                        gas_hint: 20,
+                        metadata: Some("second".to_string()),
+                        program: vec![MicroOp::SSTORE { key: key(4) }],
+                    },
+                ],
+            },
+        ];
+
+        for tcase in test_cases {
+            let encoded = encode_block(&tcase.txs);
+            let decoded = decode_block(&encoded);
+
+            assert_eq!(
+                decoded.len(),
+                tcase.txs.len(),
+                "tx count mismatch for case: {}",
+                tcase.name
+            );
+            for (got, want) in decoded.iter().zip(tcase.txs.iter()) {
+                assert_eq!(got.id, want.id, "id mismatch for case: {}", tcase.name);
+                assert_eq!(
+                    got.gas_hint, want.gas_hint,
+                    "gas_hint mismatch for case: {}",
+                    tcase.name
+                );
+                assert_eq!(
+                    got.metadata, want.metadata,
+                    "metadata mismatch for case: {}",
+                    tcase.name
+                );
+                assert_eq!(
+                    got.reads, want.reads,
+                    "reads mismatch for case: {}",
+                    tcase.name
+                );
+                assert_eq!(
+                    got.writes, want.writes,
+                    "writes mismatch for case: {}",
+                    tcase.name
+                );
+                assert_eq!(
+                    got.program.len(),
+                    want.program.len(),
+                    "program length mismatch for case: {}",
+                    tcase.name
+                );
+            }
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use crate::types::{FlatKey, FlatValue};
+use rocksdb::{ColumnFamilyDescriptor, Options, DB};
 use std::collections::HashMap;
 
 pub trait StateDB {
-    fn get_state(&self, key: &FlatKey) -> Option<&FlatValue>;
+    fn get_state(&self, key: &FlatKey) -> Option<FlatValue>;
     fn set_state(&mut self, key: FlatKey, val: FlatValue);
 }
 
@@ -23,11 +24,115 @@ impl MapState {
 }
 
 impl StateDB for MapState {
-    fn get_state(&self, key: &FlatKey) -> Option<&FlatValue> {
-        self.state.get(key)
+    fn get_state(&self, key: &FlatKey) -> Option<FlatValue> {
+        self.state.get(key).copied()
     }
 
     fn set_state(&mut self, key: FlatKey, val: FlatValue) {
         self.state.insert(key, val);
     }
 }
+
+/// Persistent `StateDB` backed by RocksDB, so a block's final state survives
+/// across separate `--exec` runs instead of vanishing with the process.
+/// Plain reads/writes against a single column family, with no multi-version
+/// or rollback machinery of its own -- `run_serial` and `mis::level_parallel_execute`
+/// both drive it directly, since neither ever needs to roll a write back
+/// (level-parallel's levels are conflict-free by construction, and
+/// `block_stm`'s speculative execution keeps its own versions in `MVMemory`
+/// rather than writing through a `StateDB` at all).
+pub struct RocksState {
+    db: DB,
+}
+
+impl RocksState {
+    const CF_STATE: &'static str = "state";
+
+    pub fn open(path: &str) -> Self {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let cf = ColumnFamilyDescriptor::new(Self::CF_STATE, Options::default());
+        let db = DB::open_cf_descriptors(&opts, path, vec![cf])
+            .expect("failed to open rocksdb state backend");
+
+        RocksState { db }
+    }
+
+    fn cf(&self) -> &rocksdb::ColumnFamily {
+        self.db
+            .cf_handle(Self::CF_STATE)
+            .expect("state column family missing")
+    }
+
+    fn read_raw(&self, key: &FlatKey) -> Option<FlatValue> {
+        self.db
+            .get_cf(self.cf(), key)
+            .expect("rocksdb get failed")
+            .map(|bytes| FlatValue::from_le_bytes(bytes.try_into().expect("corrupt stored value")))
+    }
+
+    fn write_raw(&self, key: FlatKey, val: FlatValue) {
+        self.db
+            .put_cf(self.cf(), key, val.to_le_bytes())
+            .expect("rocksdb put failed");
+    }
+
+    /// Number of keys written so far, mirroring `MapState::len` so callers
+    /// can report final state size the same way regardless of backend.
+    pub fn len(&self) -> usize {
+        self.db
+            .iterator_cf(self.cf(), rocksdb::IteratorMode::Start)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl StateDB for RocksState {
+    fn get_state(&self, key: &FlatKey) -> Option<FlatValue> {
+        self.read_raw(key)
+    }
+
+    fn set_state(&mut self, key: FlatKey, val: FlatValue) {
+        self.write_raw(key, val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A process-unique scratch directory so parallel test runs don't
+    /// collide on the same RocksDB path.
+    fn temp_db_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("pevm_rocks_state_test_{}_{}", name, std::process::id()))
+            .to_str()
+            .expect("temp path must be valid utf8")
+            .to_string()
+    }
+
+    #[test]
+    fn get_set_round_trip() {
+        let path = temp_db_path("get_set_round_trip");
+        let _ = std::fs::remove_dir_all(&path);
+
+        let mut state = RocksState::open(&path);
+        let key: FlatKey = [7u8; 32];
+
+        assert_eq!(state.get_state(&key), None);
+
+        state.set_state(key, FlatValue::from_u64(42));
+        assert_eq!(state.get_state(&key), Some(FlatValue::from_u64(42)));
+
+        state.set_state(key, FlatValue::from_u64(99));
+        assert_eq!(state.get_state(&key), Some(FlatValue::from_u64(99)));
+
+        drop(state);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -7,11 +7,20 @@ use std::hash::{Hash, Hasher};
 use std::io::BufReader;
 use std::time::Instant;
 
+mod block_stm;
+mod codec;
 mod db;
+mod mis;
 mod types;
 use db::*;
 use types::*;
 
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq, Eq)]
+enum Backend {
+    Map,
+    Rocks,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -41,6 +50,24 @@ struct Args {
 
     #[arg(long, default_value_t = 42)]
     seed: u64,
+
+    /// Run the Block-STM optimistic parallel executor instead of the serial one.
+    /// Only with `--backend map`; with `--backend rocks` execution stays serial.
+    #[arg(long)]
+    parallel: bool,
+
+    #[arg(long, default_value_t = 4)]
+    n_workers: usize,
+
+    /// Run the static conflict-graph, level-by-level parallel executor instead of the serial one.
+    #[arg(long)]
+    level_parallel: bool,
+
+    #[arg(long, value_enum, default_value = "map")]
+    backend: Backend,
+
+    #[arg(long, default_value = "rocks_state")]
+    db_path: String,
 }
 
 fn random_address<R: Rng>(rng: &mut R) -> Address {
@@ -67,25 +94,59 @@ fn key_from_idx(idx: usize, addr_pool: &[Address]) -> Key {
     Key { address, slot }
 }
 
-/// Program: only SLOAD, SSTORE, ADD, KECCAK, NOOP.
-/// First write = txid, subsequent writes increment by 1.
+/// Program: SLOAD, SSTORE, ADD, MUL, SUB, DUP, POP, KECCAK, NOOP over an
+/// operand stack.
+///
+/// Every loaded read is folded into one word via KECCAK, so each write's
+/// stored value genuinely derives from what was read instead of following a
+/// fixed, reads-independent pattern.
 fn build_program_for_tx(txid: u64, reads: &[Key], writes: &[Key]) -> Vec<MicroOp> {
     let mut prog = Vec::new();
 
-    // Read keys: load, then add something
     for r in reads {
         prog.push(MicroOp::SLOAD { key: r.clone() });
-        prog.push(MicroOp::ADD { imm: txid });
     }
 
-    // Write keys: first write txid, then increment by 1 each time
+    // KECCAK needs at least one operand, so fall back to a plain ADD of
+    // txid when there's nothing to read.
+    if reads.is_empty() {
+        prog.push(MicroOp::ADD {
+            imm: FlatValue::from_u64(txid),
+        });
+    } else {
+        prog.push(MicroOp::KECCAK { n: reads.len() });
+        prog.push(MicroOp::ADD {
+            imm: FlatValue::from_u64(txid),
+        });
+    }
+
+    // Exercise SUB on a scratch copy; the combined value itself is left
+    // untouched underneath for the writes below.
+    prog.push(MicroOp::DUP);
+    prog.push(MicroOp::DUP);
+    prog.push(MicroOp::ADD {
+        imm: FlatValue::from_u64(1),
+    });
+    prog.push(MicroOp::SUB);
+    prog.push(MicroOp::POP);
+
+    // Write keys: each write derives its stored value from the combined
+    // value via MUL, perturbed by its position, then reads it straight
+    // back -- the combined value itself is left on the stack for the next
+    // write.
     for (i, w) in writes.iter().enumerate() {
-        // simulate using ADD to adjust stack value to this target
-        prog.push(MicroOp::ADD { imm: i as u64 });
+        prog.push(MicroOp::DUP);
+        prog.push(MicroOp::DUP);
+        prog.push(MicroOp::ADD {
+            imm: FlatValue::from_u64(i as u64),
+        });
+        prog.push(MicroOp::MUL);
         prog.push(MicroOp::SSTORE { key: w.clone() });
         prog.push(MicroOp::SLOAD { key: w.clone() });
+        prog.push(MicroOp::POP);
     }
 
+    prog.push(MicroOp::POP);
     prog.push(MicroOp::NOOP);
     prog
 }
@@ -147,31 +208,57 @@ fn generate_block(args: &Args) -> Vec<Tx> {
     txs
 }
 
-fn exec_tx(tx: &Tx, state: &mut impl StateDB) -> TxRWSet {
+pub(crate) fn exec_tx(tx: &Tx, state: &mut impl StateDB) -> TxRWSet {
     let mut reads = BTreeSet::new();
     let mut writes = BTreeSet::new();
-    let mut acc = 0;
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    let mut stack: Vec<FlatValue> = Vec::new();
 
     for op in tx.program.iter() {
         match op {
             MicroOp::SLOAD { key } => {
-                key.hash(&mut hasher);
-                let h = hasher.finish();
-
-                let v = state.get_state(&h).unwrap();
-                acc += v;
+                let h = key.flat();
+
+                // A key nobody has written yet is untouched storage, which
+                // reads as its EVM default of zero, not a missing-state
+                // error -- `--cold-ratio`/neutral-index reads out of
+                // `generate_block` hit this on essentially every run.
+                let v = state.get_state(&h).unwrap_or(FlatValue::ZERO);
+                stack.push(v);
                 reads.insert(h);
             }
             MicroOp::SSTORE { key } => {
-                key.hash(&mut hasher);
-                let h = hasher.finish();
+                let h = key.flat();
+                let v = stack.pop().expect("stack underflow on SSTORE");
 
-                state.set_state(h, acc);
+                state.set_state(h, v);
                 writes.insert(h);
             }
             MicroOp::ADD { imm } => {
-                acc += *imm;
+                let a = stack.pop().unwrap_or(FlatValue::ZERO);
+                stack.push(a + *imm);
+            }
+            MicroOp::MUL => {
+                let b = stack.pop().expect("stack underflow on MUL");
+                let a = stack.pop().expect("stack underflow on MUL");
+                stack.push(a.wrapping_mul(b));
+            }
+            MicroOp::SUB => {
+                let b = stack.pop().expect("stack underflow on SUB");
+                let a = stack.pop().expect("stack underflow on SUB");
+                stack.push(a.wrapping_sub(b));
+            }
+            MicroOp::DUP => {
+                let top = *stack.last().expect("stack underflow on DUP");
+                stack.push(top);
+            }
+            MicroOp::POP => {
+                stack.pop().expect("stack underflow on POP");
+            }
+            MicroOp::KECCAK { n } => {
+                let len = stack.len();
+                assert!(len >= *n, "stack underflow on KECCAK");
+                let words = stack.split_off(len - n);
+                stack.push(FlatValue::keccak(&words));
             }
             MicroOp::NOOP => {}
         }
@@ -184,15 +271,48 @@ fn exec_tx(tx: &Tx, state: &mut impl StateDB) -> TxRWSet {
     }
 }
 
+fn run_serial(txs: &[Tx], state: &mut impl StateDB) -> Vec<TxRWSet> {
+    txs.iter().map(|tx| exec_tx(tx, state)).collect()
+}
+
 fn serial_execute(txs: &[Tx]) -> (MapState, Vec<TxRWSet>) {
     let mut state = MapState::new();
-    let mut results: Vec<TxRWSet> = Vec::new();
+    let results = run_serial(txs, &mut state);
+    (state, results)
+}
 
-    for tx in txs.iter() {
-        let res = exec_tx(tx, &mut state);
-        results.push(res);
+/// Picks the wire format from `path`'s extension: `.bin` for the compact
+/// binary codec, anything else falls back to the original pretty JSON.
+fn save_block(path: &str, txs: &[Tx]) {
+    if path.ends_with(".bin") {
+        let bytes = codec::encode_block(txs);
+        std::fs::write(path, bytes).expect("failed to write binary block");
+    } else {
+        let f = File::create(path).expect("failed to create out file");
+        serde_json::to_writer_pretty(f, txs).expect("failed to write json");
+    }
+}
+
+fn load_block(path: &str) -> Vec<Tx> {
+    if path.ends_with(".bin") {
+        let bytes = std::fs::read(path).expect("failed to read binary block");
+        codec::decode_block(&bytes)
+    } else {
+        let f = File::open(path).expect("failed to open in file");
+        let reader = BufReader::new(f);
+        serde_json::from_reader(reader).expect("failed to parse json")
+    }
+}
+
+fn print_results(results: &[TxRWSet]) {
+    for r in results.iter() {
+        println!(
+            "tx {}: reads={} writes={}",
+            r.id,
+            r.reads.len(),
+            r.writes.len()
+        );
     }
-    (state, results)
 }
 
 fn main() {
@@ -200,33 +320,84 @@ fn main() {
 
     if args.generate {
         let txs = generate_block(&args);
-        let f = File::create(&args.out).expect("failed to create out file");
-        serde_json::to_writer_pretty(f, &txs).expect("failed to write json");
+        save_block(&args.out, &txs);
         println!("Generated {} txs -> {}", txs.len(), args.out);
         return;
     }
 
     if args.exec {
-        let f = File::open(&args.in_file).expect("failed to open in file");
-        let reader = BufReader::new(f);
-        let txs: Vec<Tx> = serde_json::from_reader(reader).expect("failed to parse json");
-        println!("Loaded {} txs. Running serial execution...", txs.len());
+        let txs = load_block(&args.in_file);
+
+        if args.backend == Backend::Rocks {
+            let mut state = RocksState::open(&args.db_path);
+            let t0 = Instant::now();
+            let results = if args.level_parallel {
+                println!(
+                    "Loaded {} txs. Running level-parallel execution from the static conflict graph against RocksDB at {}...",
+                    txs.len(),
+                    args.db_path
+                );
+                let (s, results, levels) = mis::level_parallel_execute(&txs, state);
+                state = s;
+                println!(
+                    "Achieved parallelism: {} levels for {} txs",
+                    levels.len(),
+                    txs.len()
+                );
+                results
+            } else {
+                // block_stm's Block-STM executor keeps its own versions in
+                // `MVMemory` instead of writing through a `StateDB`, so
+                // `--parallel` has no seam to drive RocksDB through and
+                // falls back to plain serial execution here.
+                println!(
+                    "Loaded {} txs. Running serial execution against RocksDB at {}...",
+                    txs.len(),
+                    args.db_path
+                );
+                run_serial(&txs, &mut state)
+            };
+            let dt = t0.elapsed();
+            println!(
+                "Execution took: {:?} final state size={}",
+                dt,
+                state.len()
+            );
+            print_results(&results);
+            return;
+        }
+
         let t0 = Instant::now();
-        let (state, results) = serial_execute(&txs[0..1]);
+        let (state, results) = if args.parallel {
+            println!(
+                "Loaded {} txs. Running parallel (Block-STM) execution with {} workers...",
+                txs.len(),
+                args.n_workers
+            );
+            block_stm::parallel_execute(&txs, args.n_workers)
+        } else if args.level_parallel {
+            println!(
+                "Loaded {} txs. Running level-parallel execution from the static conflict graph...",
+                txs.len()
+            );
+            let (state, results, levels) = mis::level_parallel_execute(&txs, MapState::new());
+            println!(
+                "Achieved parallelism: {} levels for {} txs",
+                levels.len(),
+                txs.len()
+            );
+            (state, results)
+        } else {
+            println!("Loaded {} txs. Running serial execution...", txs.len());
+            serial_execute(&txs)
+        };
         let dt = t0.elapsed();
         println!(
-            "Serial execution took: {:?} final state size={}",
+            "Execution took: {:?} final state size={}",
             dt,
             state.len()
         );
-        for r in results.iter() {
-            println!(
-                "tx {}: reads={} writes={}",
-                r.id,
-                r.reads.len(),
-                r.writes.len()
-            );
-        }
+        print_results(&results);
         return;
     }
 
@@ -1,22 +1,19 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use crate::types::*;
+use rayon::prelude::*;
 
-pub struct TxRWSet {
-    pub id: u64,
-    pub reads: BTreeSet<FlatKey>,
-    pub writes: BTreeSet<FlatKey>,
-}
+use crate::db::StateDB;
+use crate::types::*;
 
-fn build_conflict_graph(txs: &Vec<TxRWSet>) -> BTreeMap<usize, BTreeSet<usize>> {
+pub(crate) fn build_conflict_graph(txs: &Vec<TxRWSet>) -> BTreeMap<usize, BTreeSet<usize>> {
     let mut readers: BTreeMap<FlatKey, BTreeSet<usize>> = BTreeMap::new();
     let mut writers: BTreeMap<FlatKey, BTreeSet<usize>> = BTreeMap::new();
     for tx in txs {
         for k in &tx.reads {
-            readers.entry(k.clone()).or_default().insert(tx.id as usize);
+            readers.entry(*k).or_default().insert(tx.id as usize);
         }
         for k in &tx.writes {
-            writers.entry(k.clone()).or_default().insert(tx.id as usize);
+            writers.entry(*k).or_default().insert(tx.id as usize);
         }
     }
 
@@ -45,11 +42,128 @@ fn build_conflict_graph(txs: &Vec<TxRWSet>) -> BTreeMap<usize, BTreeSet<usize>>
 
     // add others
     for i in 0..txs.len() {
-        if !graph.contains_key(&i) {
-            graph.insert(i, BTreeSet::new());
+        graph.entry(i).or_default();
+    }
+    graph
+}
+
+/// Turns the undirected conflict graph into a dependency DAG by orienting
+/// every edge from the lower tx id to the higher one (block order always
+/// wins), then layers it with Kahn's algorithm so each returned level holds
+/// only mutually independent txs.
+pub(crate) fn schedule_levels(graph: &BTreeMap<usize, BTreeSet<usize>>) -> Vec<Vec<usize>> {
+    // `graph` only stores each conflict from one side (e.g. a WR edge is
+    // recorded on the reader, not the writer), so normalize every conflict
+    // to an unordered pair before orienting it low -> high.
+    let mut edges: BTreeSet<(usize, usize)> = BTreeSet::new();
+    for (&a, neighbors) in graph {
+        for &b in neighbors {
+            edges.insert(if a < b { (a, b) } else { (b, a) });
         }
     }
-    return graph;
+
+    let mut successors: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+    let mut in_degree: BTreeMap<usize, usize> = BTreeMap::new();
+    for &id in graph.keys() {
+        successors.entry(id).or_default();
+        in_degree.entry(id).or_insert(0);
+    }
+    for (lo, hi) in edges {
+        successors.entry(lo).or_default().insert(hi);
+        *in_degree.entry(hi).or_insert(0) += 1;
+    }
+
+    let mut remaining = in_degree.clone();
+    let mut frontier: Vec<usize> = remaining
+        .iter()
+        .filter(|&(_, &d)| d == 0)
+        .map(|(&id, _)| id)
+        .collect();
+    frontier.sort();
+
+    let mut levels = Vec::new();
+    while !frontier.is_empty() {
+        let mut next: BTreeSet<usize> = BTreeSet::new();
+        for &id in &frontier {
+            for &succ in &successors[&id] {
+                let d = remaining.get_mut(&succ).unwrap();
+                *d -= 1;
+                if *d == 0 {
+                    next.insert(succ);
+                }
+            }
+        }
+        levels.push(frontier);
+        frontier = next.into_iter().collect();
+    }
+
+    levels
+}
+
+/// Overlays speculative writes on top of an already-committed `StateDB` so
+/// several txs in the same level can execute against a shared snapshot
+/// without taking a lock on it.
+struct WriteBuffer<'a, S: StateDB> {
+    base: &'a S,
+    writes: HashMap<FlatKey, FlatValue>,
+}
+
+impl<'a, S: StateDB> StateDB for WriteBuffer<'a, S> {
+    fn get_state(&self, key: &FlatKey) -> Option<FlatValue> {
+        self.writes
+            .get(key)
+            .copied()
+            .or_else(|| self.base.get_state(key))
+    }
+
+    fn set_state(&mut self, key: FlatKey, val: FlatValue) {
+        self.writes.insert(key, val);
+    }
+}
+
+/// Runs `txs` level by level from their static conflict graph: every level
+/// is executed with rayon across all its txs concurrently (by construction
+/// no tx in a level reads or writes a key another one in it writes; shared
+/// reads are allowed), and writes are merged into `state`
+/// between levels. Generic over `StateDB` so callers can drive this against
+/// either `MapState` or `RocksState`. Also returns the levels themselves so
+/// callers can report achieved parallelism (level count vs. tx count).
+pub fn level_parallel_execute<S: StateDB + Sync>(
+    txs: &[Tx],
+    mut state: S,
+) -> (S, Vec<TxRWSet>, Vec<Vec<usize>>) {
+    let rw_sets: Vec<TxRWSet> = txs.iter().map(|tx| tx.flat_rw_set()).collect();
+    let graph = build_conflict_graph(&rw_sets);
+    let levels = schedule_levels(&graph);
+
+    let mut results: Vec<Option<TxRWSet>> = (0..txs.len()).map(|_| None).collect();
+
+    for level in &levels {
+        let level_results: Vec<(usize, HashMap<FlatKey, FlatValue>, TxRWSet)> = level
+            .par_iter()
+            .map(|&idx| {
+                let mut buf = WriteBuffer {
+                    base: &state,
+                    writes: HashMap::new(),
+                };
+                let rw = crate::exec_tx(&txs[idx], &mut buf);
+                (idx, buf.writes, rw)
+            })
+            .collect();
+
+        for (idx, writes, rw) in level_results {
+            for (k, v) in writes {
+                state.set_state(k, v);
+            }
+            results[idx] = Some(rw);
+        }
+    }
+
+    let results: Vec<TxRWSet> = results
+        .into_iter()
+        .map(|r| r.expect("every tx belongs to exactly one level"))
+        .collect();
+    (state, results, levels)
 }
 
 #[cfg(test)]
@@ -61,6 +175,14 @@ mod tests {
         keys.iter().cloned().collect()
     }
 
+    /// A `FlatKey` with a distinct, recognizable tag in its first byte, for
+    /// tests that only care that keys are distinguishable from each other.
+    fn k(tag: u8) -> FlatKey {
+        let mut key = [0u8; 32];
+        key[0] = tag;
+        key
+    }
+
     #[test]
     fn test_conflict_graph_multiple_cases() {
         struct TestCase {
@@ -75,18 +197,18 @@ mod tests {
                 txs: vec![
                     TxRWSet {
                         id: 0,
-                        reads: set(&[1]),
-                        writes: set(&[2]),
+                        reads: set(&[k(1)]),
+                        writes: set(&[k(2)]),
                     },
                     TxRWSet {
                         id: 1,
-                        reads: set(&[3]),
-                        writes: set(&[4]),
+                        reads: set(&[k(3)]),
+                        writes: set(&[k(4)]),
                     },
                     TxRWSet {
                         id: 2,
-                        reads: set(&[5]),
-                        writes: set(&[6]),
+                        reads: set(&[k(5)]),
+                        writes: set(&[k(6)]),
                     },
                 ],
                 expected: vec![
@@ -100,17 +222,17 @@ mod tests {
                 txs: vec![
                     TxRWSet {
                         id: 0,
-                        reads: set(&[0xa, 0xb]),
-                        writes: set(&[0xc]),
+                        reads: set(&[k(0xa), k(0xb)]),
+                        writes: set(&[k(0xc)]),
                     },
                     TxRWSet {
                         id: 1,
-                        reads: set(&[0xc]),
-                        writes: set(&[0xd]),
+                        reads: set(&[k(0xc)]),
+                        writes: set(&[k(0xd)]),
                     },
                     TxRWSet {
                         id: 2,
-                        reads: set(&[0xd]),
+                        reads: set(&[k(0xd)]),
                         writes: BTreeSet::new(),
                     },
                 ],
@@ -126,12 +248,12 @@ mod tests {
                     TxRWSet {
                         id: 0,
                         reads: set(&[]),
-                        writes: set(&[1]),
+                        writes: set(&[k(1)]),
                     },
                     TxRWSet {
                         id: 1,
                         reads: set(&[]),
-                        writes: set(&[1]),
+                        writes: set(&[k(1)]),
                     },
                 ],
                 expected: vec![
@@ -145,17 +267,17 @@ mod tests {
                     TxRWSet {
                         id: 0,
                         reads: set(&[]),
-                        writes: set(&[10]),
+                        writes: set(&[k(10)]),
                     },
                     TxRWSet {
                         id: 1,
-                        reads: set(&[10]),
-                        writes: set(&[11]),
+                        reads: set(&[k(10)]),
+                        writes: set(&[k(11)]),
                     },
                     TxRWSet {
                         id: 2,
-                        reads: set(&[11]),
-                        writes: set(&[10]),
+                        reads: set(&[k(11)]),
+                        writes: set(&[k(10)]),
                     },
                 ],
                 expected: vec![
@@ -187,4 +309,96 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_schedule_levels_multiple_cases() {
+        struct TestCase {
+            name: &'static str,
+            txs: Vec<TxRWSet>,
+            expected: Vec<Vec<usize>>,
+        }
+
+        let test_cases = vec![
+            TestCase {
+                name: "no_conflict_disjoint_keys",
+                txs: vec![
+                    TxRWSet {
+                        id: 0,
+                        reads: set(&[k(1)]),
+                        writes: set(&[k(2)]),
+                    },
+                    TxRWSet {
+                        id: 1,
+                        reads: set(&[k(3)]),
+                        writes: set(&[k(4)]),
+                    },
+                    TxRWSet {
+                        id: 2,
+                        reads: set(&[k(5)]),
+                        writes: set(&[k(6)]),
+                    },
+                ],
+                // every tx is independent, so they all land in one level
+                expected: vec![vec![0, 1, 2]],
+            },
+            TestCase {
+                name: "basic_rw_chain",
+                txs: vec![
+                    TxRWSet {
+                        id: 0,
+                        reads: set(&[k(0xa), k(0xb)]),
+                        writes: set(&[k(0xc)]),
+                    },
+                    TxRWSet {
+                        id: 1,
+                        reads: set(&[k(0xc)]),
+                        writes: set(&[k(0xd)]),
+                    },
+                    TxRWSet {
+                        id: 2,
+                        reads: set(&[k(0xd)]),
+                        writes: BTreeSet::new(),
+                    },
+                ],
+                // each tx depends on the previous one, so they're fully serialized
+                expected: vec![vec![0], vec![1], vec![2]],
+            },
+            TestCase {
+                name: "one_hot_key_fans_out_then_back_in",
+                txs: vec![
+                    TxRWSet {
+                        id: 0,
+                        reads: set(&[]),
+                        writes: set(&[k(100)]),
+                    },
+                    TxRWSet {
+                        id: 1,
+                        reads: set(&[k(100)]),
+                        writes: set(&[k(1)]),
+                    },
+                    TxRWSet {
+                        id: 2,
+                        reads: set(&[k(100)]),
+                        writes: set(&[k(2)]),
+                    },
+                    TxRWSet {
+                        id: 3,
+                        reads: set(&[k(1), k(2)]),
+                        writes: BTreeSet::new(),
+                    },
+                ],
+                expected: vec![vec![0], vec![1, 2], vec![3]],
+            },
+        ];
+
+        for tcase in test_cases {
+            let graph = build_conflict_graph(&tcase.txs);
+            let levels = schedule_levels(&graph);
+            assert_eq!(
+                levels, tcase.expected,
+                "Level schedule mismatch for case: {}",
+                tcase.name
+            );
+        }
+    }
 }
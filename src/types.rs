@@ -1,14 +1,139 @@
 use hex::{decode, encode};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha3::{Digest, Keccak256};
 use std::collections::BTreeSet;
 
 pub type Address = [u8; 20];
 pub type Slot = [u8; 32];
 
-// TODO: replace it with u256 and use safe math to avoid overflow
-pub type FlatKey = u64;
-pub type FlatValue = u64;
+/// Storage key: the keccak256 of a `Key`'s address+slot, so two different
+/// `(address, slot)` pairs can't collide onto the same flat key the way a
+/// 64-bit `DefaultHasher` could.
+pub type FlatKey = [u8; 32];
+
+/// 256-bit EVM word, stored as four little-endian `u64` limbs.
+pub type FlatValue = U256;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0, 0, 0, 0]);
+
+    pub fn from_u64(v: u64) -> Self {
+        U256([v, 0, 0, 0])
+    }
+
+    /// Addition modulo 2^256, i.e. the EVM's `ADD` semantics: the carry out
+    /// of the top limb is simply dropped instead of panicking or saturating.
+    pub fn wrapping_add(self, rhs: Self) -> Self {
+        let mut limbs = [0u64; 4];
+        let mut carry = 0u128;
+        for (limb, (&a, &b)) in limbs.iter_mut().zip(self.0.iter().zip(rhs.0.iter())) {
+            let sum = a as u128 + b as u128 + carry;
+            *limb = sum as u64;
+            carry = sum >> 64;
+        }
+        U256(limbs)
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            out[i * 8..i * 8 + 8].copy_from_slice(&limb.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        U256(limbs)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut out = self.to_le_bytes();
+        out.reverse();
+        out
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        let mut le = bytes;
+        le.reverse();
+        Self::from_le_bytes(le)
+    }
+
+    /// Subtraction modulo 2^256, i.e. the EVM's `SUB` semantics: underflow
+    /// wraps around instead of panicking or saturating at zero.
+    pub fn wrapping_sub(self, rhs: Self) -> Self {
+        let mut limbs = [0u64; 4];
+        let mut borrow = 0i128;
+        for (limb, (&a, &b)) in limbs.iter_mut().zip(self.0.iter().zip(rhs.0.iter())) {
+            let diff = a as i128 - b as i128 - borrow;
+            if diff < 0 {
+                *limb = (diff + (1i128 << 64)) as u64;
+                borrow = 1;
+            } else {
+                *limb = diff as u64;
+                borrow = 0;
+            }
+        }
+        U256(limbs)
+    }
+
+    /// Multiplication modulo 2^256, i.e. the EVM's `MUL` semantics: the
+    /// schoolbook product is truncated to its low 256 bits.
+    pub fn wrapping_mul(self, rhs: Self) -> Self {
+        let mut acc = [0u64; 8];
+        for i in 0..4 {
+            let mut carry: u128 = 0;
+            for j in 0..4 {
+                let idx = i + j;
+                let prod = self.0[i] as u128 * rhs.0[j] as u128 + acc[idx] as u128 + carry;
+                acc[idx] = prod as u64;
+                carry = prod >> 64;
+            }
+            let mut idx = i + 4;
+            while carry > 0 {
+                let sum = acc[idx] as u128 + carry;
+                acc[idx] = sum as u64;
+                carry = sum >> 64;
+                idx += 1;
+            }
+        }
+        U256([acc[0], acc[1], acc[2], acc[3]])
+    }
+
+    /// keccak256 of the big-endian concatenation of `words`, as a single
+    /// output word. Backs the micro-VM's `KECCAK` opcode.
+    pub fn keccak(words: &[U256]) -> U256 {
+        let mut hasher = Keccak256::new();
+        for w in words {
+            hasher.update(w.to_be_bytes());
+        }
+        let digest = hasher.finalize();
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&digest);
+        U256::from_be_bytes(out)
+    }
+}
+
+impl std::ops::Add for U256 {
+    type Output = U256;
+
+    fn add(self, rhs: U256) -> U256 {
+        self.wrapping_add(rhs)
+    }
+}
+
+impl std::ops::AddAssign for U256 {
+    fn add_assign(&mut self, rhs: U256) {
+        *self = self.wrapping_add(rhs);
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Key {
@@ -16,6 +141,20 @@ pub struct Key {
     pub slot: Slot,
 }
 
+impl Key {
+    /// Derives this key's storage slot as keccak256(address || slot), the
+    /// same way the EVM addresses contract storage.
+    pub fn flat(&self) -> FlatKey {
+        let mut hasher = Keccak256::new();
+        hasher.update(self.address);
+        hasher.update(self.slot);
+        let digest = hasher.finalize();
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&digest);
+        out
+    }
+}
+
 impl Serialize for Key {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -81,10 +220,53 @@ pub struct TxRWSet {
     pub writes: BTreeSet<FlatKey>,
 }
 
+impl Tx {
+    /// Replays this tx's program just far enough to know which `FlatKey`s it
+    /// touches, without resolving any values. Lets a conflict graph be built
+    /// from `program` alone (the `reads`/`writes` fields are not consulted)
+    /// before anything has actually run.
+    pub fn flat_rw_set(&self) -> TxRWSet {
+        let mut reads = BTreeSet::new();
+        let mut writes = BTreeSet::new();
+        for op in &self.program {
+            match op {
+                MicroOp::SLOAD { key } => {
+                    reads.insert(key.flat());
+                }
+                MicroOp::SSTORE { key } => {
+                    writes.insert(key.flat());
+                }
+                MicroOp::ADD { .. }
+                | MicroOp::MUL
+                | MicroOp::SUB
+                | MicroOp::DUP
+                | MicroOp::POP
+                | MicroOp::KECCAK { .. }
+                | MicroOp::NOOP => {}
+            }
+        }
+        TxRWSet {
+            id: self.id,
+            reads,
+            writes,
+        }
+    }
+}
+
+/// A tiny stack-based micro-VM's instruction set. `SLOAD`/`SSTORE` push/pop
+/// storage values; `ADD`/`MUL`/`SUB` are arithmetic (`ADD` takes its second
+/// operand as an immediate, `MUL`/`SUB` pop both operands); `DUP`/`POP`
+/// manipulate the stack directly; `KECCAK { n }` pops `n` words and pushes
+/// their keccak256 hash.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum MicroOp {
     SLOAD { key: Key },
     SSTORE { key: Key },
     ADD { imm: FlatValue },
+    MUL,
+    SUB,
+    DUP,
+    POP,
+    KECCAK { n: usize },
     NOOP,
 }